@@ -1,5 +1,4 @@
 use failure::{Error, bail, format_err};
-use itertools::{join, repeat_n};
 use serde_derive::Deserialize;
 use std::convert::TryInto;
 use std::fmt::{self, Display};
@@ -8,6 +7,25 @@ use structopt::StructOpt;
 
 const DEFAULT_INDENT: &str = "  ";
 
+// renders a slice of `Display`-able items joined by `sep`, without the
+// intermediate `String` allocation `itertools::join` needs
+struct DisplaySeparated<'a, T>(&'a [T], &'a str);
+
+impl<'a, T: Display> Display for DisplaySeparated<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut items = self.0.iter();
+
+        if let Some(first) = items.next() {
+            write!(f, "{}", first)?;
+        }
+        for item in items {
+            write!(f, "{}{}", self.1, item)?;
+        }
+
+        Ok(())
+    }
+}
+
 fn main() -> Result<(), Error> {
     let opt = CliOpt::from_args();
 
@@ -18,11 +36,14 @@ fn main() -> Result<(), Error> {
 
     let statement_config: StatementConfig = toml::from_str(&input)?;
 
+    let use_cte = opt.cte || statement_config.use_cte;
+    let dialect: DialectKind = opt.dialect.as_deref().unwrap_or("clickhouse").parse()?;
+
     let statement: Statement = statement_config.try_into()?;
 
     statement.validate()?;
 
-    let sql = statement.clickhouse_sql(&indent, opt.reverse_nesting);
+    let sql = statement.to_sql(&dialect, &indent, opt.reverse_nesting, use_cte);
 
     println!("{}", sql);
 
@@ -37,6 +58,18 @@ struct StatementConfig {
     #[serde(default)]
     #[serde(rename="join_type")]
     global_join_type: JoinType,
+    #[serde(default)]
+    use_cte: bool,
+    order_by: Option<String>,
+    limit: Option<u64>,
+    offset: Option<u64>,
+    combine: Option<CombineConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CombineConfig {
+    op: SetOp,
+    selects: Vec<SelectConfig>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -45,210 +78,661 @@ struct SelectConfig {
     projections: Vec<String>,
     group_by: Option<String>,
     where_clause: Option<String>,
+    order_by: Option<String>,
+    limit: Option<u64>,
+    offset: Option<u64>,
 }
 
 #[derive(Debug, Deserialize)]
 struct Statement {
     create_table: Option<String>,
-    joins: Vec<(String, JoinType)>,
+    joins: Vec<Join>,
+    selects: Vec<Select>,
+    order_by: Option<String>,
+    limit: Option<u64>,
+    offset: Option<u64>,
+    combine: Option<Combine>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Combine {
+    op: SetOp,
     selects: Vec<Select>,
 }
 
+// a join's type (`{type} JOIN`, `ALL {type} JOIN` in ClickHouse) and its
+// constraint -- either a bare column (`USING col`) or an arbitrary
+// condition (`ON l.col = r.col`)
+#[derive(Debug, Clone, Deserialize)]
+struct Join {
+    constraint: JoinConstraint,
+    join_type: JoinType,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+enum JoinConstraint {
+    On(String),
+    Using(String),
+}
+
+// controls the dialect-specific bits of SQL rendering: join keywords,
+// identifier quoting, and how LIMIT/OFFSET are spelled. indentation is not
+// this trait's concern -- the `ast` module applies it as a formatting pass
+// over the tree, so these methods only ever deal in bare clause text.
+trait Dialect {
+    fn join_type_sql(&self, join_type: &JoinType) -> String;
+    fn constraint_sql(&self, constraint: &JoinConstraint) -> String;
+    fn limit_offset_sql(&self, limit: Option<u64>, offset: Option<u64>) -> String;
+    fn quote_ident(&self, ident: &str) -> String;
+}
+
+#[derive(Debug, Clone, Copy)]
+enum DialectKind {
+    ClickHouse,
+    Postgres,
+    Ansi,
+}
+
+impl Dialect for DialectKind {
+    fn join_type_sql(&self, join_type: &JoinType) -> String {
+        match self {
+            // ClickHouse requires the join strictness keyword `ALL` in
+            // front of the join type; ANSI/Postgres have no such concept
+            DialectKind::ClickHouse => format!("ALL {} JOIN", join_type),
+            DialectKind::Postgres | DialectKind::Ansi => format!("{} JOIN", join_type),
+        }
+    }
+
+    fn constraint_sql(&self, constraint: &JoinConstraint) -> String {
+        match constraint {
+            JoinConstraint::On(expr) => format!("ON {}", expr),
+            // ANSI requires the USING column list to be parenthesized
+            JoinConstraint::Using(col) => match self {
+                DialectKind::Ansi => format!("USING ({})", col),
+                DialectKind::ClickHouse | DialectKind::Postgres => format!("USING {}", col),
+            },
+        }
+    }
+
+    fn limit_offset_sql(&self, limit: Option<u64>, offset: Option<u64>) -> String {
+        let mut res = String::new();
+
+        match self {
+            DialectKind::ClickHouse | DialectKind::Postgres => {
+                if let Some(limit) = limit {
+                    res.push_str(&format!("\nLIMIT {}", limit));
+                }
+                if let Some(offset) = offset {
+                    res.push_str(&format!("\nOFFSET {}", offset));
+                }
+            },
+            // ANSI has no LIMIT/OFFSET keywords, only the OFFSET ... FETCH form
+            DialectKind::Ansi => {
+                if let Some(offset) = offset {
+                    res.push_str(&format!("\nOFFSET {} ROWS", offset));
+                }
+                if let Some(limit) = limit {
+                    res.push_str(&format!("\nFETCH FIRST {} ROWS ONLY", limit));
+                }
+            },
+        }
+
+        res
+    }
+
+    fn quote_ident(&self, ident: &str) -> String {
+        match self {
+            DialectKind::ClickHouse => ident.to_owned(),
+            DialectKind::Postgres | DialectKind::Ansi => format!("\"{}\"", ident),
+        }
+    }
+}
+
+impl Display for DialectKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DialectKind::ClickHouse => write!(f, "clickhouse"),
+            DialectKind::Postgres => write!(f, "postgres"),
+            DialectKind::Ansi => write!(f, "ansi"),
+        }
+    }
+}
+
+impl std::str::FromStr for DialectKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clickhouse" | "CLICKHOUSE" => Ok(DialectKind::ClickHouse),
+            "postgres" | "POSTGRES" => Ok(DialectKind::Postgres),
+            "ansi" | "ANSI" => Ok(DialectKind::Ansi),
+            _ => Err(format_err!("Could not parse string to DialectKind"))
+        }
+    }
+}
+
 impl Statement {
     fn validate(&self) -> Result<(), Error> {
+        if let Some(ref combine) = self.combine {
+            // `combine` replaces the joined-selects query entirely -- a
+            // config that sets both is ambiguous about which one wins, so
+            // reject it instead of silently dropping one
+            if !self.selects.is_empty() || !self.joins.is_empty() {
+                bail!("combine cannot be used together with top-level selects/joins");
+            }
+
+            if combine.selects.is_empty() {
+                bail!("combine must have at least one select");
+            }
+
+            let proj_count = combine.selects.first()
+                .map(|select| select.projections.len())
+                .unwrap_or(0);
+
+            if combine.selects.iter().any(|select| select.projections.len() != proj_count) {
+                bail!("combined selects must all have the same number of projections");
+            }
+
+            // each combined select is rendered bare, with no wrapping parens,
+            // immediately next to the set operator -- a select-level
+            // order_by/limit/offset would render unparenthesized there,
+            // which is a syntax error in Postgres/ANSI and fragile at best
+            // in ClickHouse. the statement-level order_by/limit/offset
+            // (applied to the combined result as a whole) is the only
+            // supported way to order/limit a combine.
+            if combine.selects.iter().any(|select| select.order_by.is_some() || select.limit.is_some() || select.offset.is_some()) {
+                bail!("combined selects cannot set their own order_by/limit/offset; set it on the statement instead");
+            }
+
+            return Ok(());
+        }
+
         // joins should have len one less than selects
         if self.joins.len() != self.selects.len() - 1 {
             bail!("joins len must be one less than selects");
         }
 
-//        // check that joins are referencing a col (aliased) on
-//        // both tables
-//        for (i, selects) in self.selects.windows(2).enumerate() {
-//            let join_tuple = &self.joins[i]; // should never panic, len checked above
-//
-//            // if joining on a tuple
-//            let leading_char = join_tuple.chars().nth(0)
-//                .ok_or_else(|| format_err!("empty join value not allowed"))?;
-//
-//            let tuple_cols = if leading_char == '(' {
-//                join_tuple.trim_start_matches('(').trim_end_matches(')').split(',')
-//                    .map(|s| s.trim().to_owned())
-//                    .collect()
-//            } else {
-//                vec![join_tuple.to_owned()]
-//            };
-//
-//            let can_join = tuple_cols.iter()
-//                .all(|col| {
-//                    selects[0].aliased_projections().contains(col)
-//                    && selects[1].aliased_projections().contains(col)
-//                });
-//
-//            if !can_join {
-//                bail!("join must match a col or col alias for both tables: {}", join_tuple);
-//            }
-//        }
+        // a lone select with no joins is rendered as the query body
+        // directly, with no wrapping subquery -- if the statement itself
+        // also sets order_by/limit/offset, the two would stack into two
+        // conflicting clauses in the same SELECT
+        if self.joins.is_empty() && self.selects.len() == 1 {
+            let stmt_has_ordering = self.order_by.is_some() || self.limit.is_some() || self.offset.is_some();
+            let select = &self.selects[0];
+            let select_has_ordering = select.order_by.is_some() || select.limit.is_some() || select.offset.is_some();
+
+            if stmt_has_ordering && select_has_ordering {
+                bail!("statement-level order_by/limit/offset cannot be combined with the lone select's own order_by/limit/offset");
+            }
+        }
+
+        // check that USING joins are referencing a col (aliased) on both
+        // tables -- an ON join can reference arbitrary expressions, so it
+        // isn't checked here
+        for (i, selects) in self.selects.windows(2).enumerate() {
+            let join = &self.joins[i]; // should never panic, len checked above
+
+            if let JoinConstraint::Using(ref col) = join.constraint {
+                let can_join = selects[0].aliased_projections().contains(col)
+                    && selects[1].aliased_projections().contains(col);
+
+                if !can_join {
+                    bail!("join must match a col or col alias for both tables: {}", col);
+                }
+            }
+        }
 
         Ok(())
     }
 
-    fn clickhouse_sql(&self, indent: &str, reverse_nesting: bool) -> String {
-        let indent_level = if self.create_table.is_some() {
-            1
+    fn to_sql(&self, dialect: &dyn Dialect, indent: &str, reverse_nesting: bool, use_cte: bool) -> String {
+        let (with, body) = if let Some(ref combine) = self.combine {
+            (vec![], Self::build_combine_tree(combine, dialect, indent))
+        } else if use_cte {
+            Self::build_cte_tree(&self.selects, &self.joins, dialect, indent)
         } else {
-            0
-        };
+            let mut selects = self.selects.clone();
+            let mut joins = self.joins.clone();
+
+            let select = Self::build_join_tree(&mut selects, &mut joins, dialect, indent, reverse_nesting);
 
-        let mut selects_working = self.selects.clone();
-        let mut joins_working = self.joins.clone();
+            (vec![], ast::SetExpr::Select(Box::new(select)))
+        };
 
-        let res = Self::sql_subquery(
-            &mut selects_working,
-            &mut joins_working,
+        let query = ast::Query {
+            with,
+            body,
+            order_by: self.order_by.clone(),
+            limit: self.limit,
+            offset: self.offset,
+            create_table: self.create_table.clone(),
+            dialect,
             indent,
-            indent_level,
-            reverse_nesting,
-        );
+        };
 
-        if let Some(ref create_table) = self.create_table {
-            format!("CREATE TABLE {} AS\n(\n{}\n)", create_table, res)
-        } else {
-            res
+        query.to_string()
+    }
+
+    // builds each select as its own named CTE (deduping collisions on
+    // `table_name` with a numeric suffix) and joins the CTEs flat, instead
+    // of nesting each join as a `FROM ( ... )` subquery
+    fn build_cte_tree<'a>(
+        selects: &[Select],
+        joins: &[Join],
+        dialect: &'a dyn Dialect,
+        indent: &'a str,
+        ) -> (Vec<ast::NamedCte<'a>>, ast::SetExpr<'a>)
+    {
+        let cte_names = Self::cte_names(selects);
+
+        let with = selects.iter()
+            .zip(cte_names.iter())
+            .map(|(select, name)| {
+                let select = ast::Select::from_domain(select.clone(), dialect, indent);
+                ast::NamedCte {
+                    name: dialect.quote_ident(name),
+                    query: ast::Query::from_select(select, dialect, indent),
+                }
+            })
+            .collect();
+
+        let all_cols = Self::deduped_aliased_cols(selects);
+
+        // a flat join chain over the CTE names themselves -- no operand is
+        // ever wrapped in its own subquery, since every operand here is
+        // just a bare CTE name
+        let mut from = ast::FromItem::Table(dialect.quote_ident(&cte_names[0]));
+
+        for (join, name) in joins.iter().zip(cte_names.iter().skip(1)) {
+            from = ast::FromItem::Join(Box::new(ast::Join {
+                left: from,
+                right: ast::FromItem::Table(dialect.quote_ident(name)),
+                operator: ast::JoinOperator {
+                    join_type: join.join_type.clone(),
+                    constraint: join.constraint.clone(),
+                    dialect,
+                },
+            }));
         }
+
+        let select = ast::Select {
+            projections: all_cols,
+            from,
+            group_by: None,
+            where_clause: None,
+            order_by: None,
+            limit: None,
+            offset: None,
+            dialect,
+            indent,
+        };
+
+        (with, ast::SetExpr::Select(Box::new(select)))
+    }
+
+    // renders each combined select as its own `SetExpr`, chained left to
+    // right with the set operator between them -- validate() has already
+    // checked the projection counts line up
+    fn build_combine_tree<'a>(combine: &Combine, dialect: &'a dyn Dialect, indent: &'a str) -> ast::SetExpr<'a> {
+        let mut selects = combine.selects.iter().cloned()
+            .map(|select| ast::SetExpr::Select(Box::new(ast::Select::from_domain(select, dialect, indent))));
+
+        let first = selects.next().expect("combine always has at least one select");
+
+        selects.fold(first, |left, right| {
+            ast::SetExpr::SetOperation(Box::new(ast::SetOperation {
+                op: combine.op.clone(),
+                left,
+                right,
+            }))
+        })
     }
 
-    fn sql_subquery(
+    // derive a CTE name from each select's `table_name`, deduped with a
+    // numeric suffix on collision
+    fn cte_names(selects: &[Select]) -> Vec<String> {
+        let mut counts: std::collections::HashMap<String, usize> = std::collections::HashMap::new();
+
+        selects.iter()
+            .map(|select| {
+                let count = counts.entry(select.table_name.clone()).or_insert(0);
+                *count += 1;
+
+                if *count == 1 {
+                    select.table_name.clone()
+                } else {
+                    format!("{}_{}", select.table_name, count)
+                }
+            })
+            .collect()
+    }
+
+    // for each of the given selects' projections, keep only the first
+    // occurrence of each alias -- used to build the deduped column list
+    // that heads a multi-way join or CTE query
+    fn deduped_aliased_cols(selects: &[Select]) -> Vec<Projection> {
+        let mut seen = std::collections::HashSet::new();
+
+        selects.iter()
+            .flat_map(|select| select.aliased_projections().into_iter())
+            .filter(|alias| seen.insert(alias.clone()))
+            .map(|alias| Projection { col: alias, alias: None })
+            .collect()
+    }
+
+    // recursively consumes `selects`/`joins` (pairing them off from the
+    // front) into a single derived-table `Select` whose `from` is a tree of
+    // `ast::Join`s -- `reverse_nesting` controls which side of each pairing
+    // nests deeper
+    fn build_join_tree<'a>(
         selects: &mut Vec<Select>,
-        joins: &mut Vec<(String, JoinType)>,
-        indent: &str,
-        indent_level: usize,
+        joins: &mut Vec<Join>,
+        dialect: &'a dyn Dialect,
+        indent: &'a str,
         reverse_nesting: bool,
-        ) -> String
+        ) -> ast::Select<'a>
     {
-        let base_indent: String = repeat_n(indent, indent_level).collect();
-        let plus_1_indent: String = repeat_n(indent, indent_level + 1).collect();
-
-        // early return for no joins
-        let (join_col, join_type) = if joins.is_empty() {
-            let res = Self::select_sql(&selects[0], indent, indent_level);
-            let res = res.trim().trim_start_matches("(\n").trim_end_matches(")").trim_end();
-            return res.to_owned();
+        if joins.is_empty() {
+            return ast::Select::from_domain(selects.remove(0), dialect, indent);
+        }
+
+        let current_join = joins.remove(0);
+        let all_cols = Self::deduped_aliased_cols(selects);
+
+        let (left, right) = if reverse_nesting {
+            let left = ast::FromItem::operand_from_domain(selects.remove(0), dialect, indent);
+
+            let right = if selects.len() >= 2 {
+                let nested = Self::build_join_tree(selects, joins, dialect, indent, reverse_nesting);
+                ast::FromItem::Subquery(Box::new(ast::Query::from_select(nested, dialect, indent)))
+            } else {
+                ast::FromItem::operand_from_domain(selects.remove(0), dialect, indent)
+            };
+
+            (left, right)
         } else {
-            joins.remove(0)
+            let join_r = selects.remove(0);
+
+            let left = if selects.len() >= 2 {
+                let nested = Self::build_join_tree(selects, joins, dialect, indent, reverse_nesting);
+                ast::FromItem::Subquery(Box::new(ast::Query::from_select(nested, dialect, indent)))
+            } else {
+                ast::FromItem::operand_from_domain(selects.remove(0), dialect, indent)
+            };
+
+            (left, ast::FromItem::operand_from_domain(join_r, dialect, indent))
         };
 
-        let mut res = format!("{}SELECT\n", base_indent);
+        ast::Select {
+            projections: all_cols,
+            from: ast::FromItem::Join(Box::new(ast::Join {
+                left,
+                right,
+                operator: ast::JoinOperator {
+                    join_type: current_join.join_type,
+                    constraint: current_join.constraint,
+                    dialect,
+                },
+            })),
+            group_by: None,
+            where_clause: None,
+            order_by: None,
+            limit: None,
+            offset: None,
+            dialect,
+            indent,
+        }
+    }
+}
 
-        // for each subquery's all cols, remove the duplicates (keep first)
-        let mut col_set = std::collections::HashSet::new();
-        let all_cols = selects.iter()
-            .flat_map(|select| {
-                select.aliased_projections().into_iter()
-            })
-            .filter(|alias| {
-                col_set.insert(alias.clone())
-            });
-
-        let separator = format!(",\n{}", plus_1_indent);
-        let all_cols_str = format!("{}{}",
-            plus_1_indent,
-            join(all_cols, &separator)
-        );
-        res.push_str(&all_cols_str);
-        res.push_str(&format!("\n{}FROM\n", base_indent));
-
-        if reverse_nesting {
-            // first half of join
-            let join_l = selects.remove(0);
-
-            res.push_str(&Self::select_sql(&join_l, indent, indent_level + 1));
-
-            res.push_str(&format!("\n{}ALL {} JOIN\n", plus_1_indent, join_type));
-
-            // subqueries
-
-            if selects.len() >= 2 {
-                res.push_str(&format!("{}(\n", plus_1_indent));
-                res.push_str(&Self::sql_subquery(
-                    selects,
-                    joins,
-                    indent,
-                    indent_level + 2,
-                    reverse_nesting,
-                ));
-                res.push_str(&format!("\n{})", plus_1_indent));
-            } else if selects.len() == 1 {
-                let join_r = selects.remove(0);
-                res.push_str(&Self::select_sql(&join_r, indent, indent_level + 1));
+// a typed SQL AST: the tree is built once from `Statement`'s domain types,
+// then rendered in a single `Display` pass. Indentation is not threaded as
+// an integer level -- each node formats its children via `Indented`, which
+// shifts every line of a child's own rendering by one indent unit, so a
+// node never needs to know its absolute depth in the tree.
+mod ast {
+    use super::{Dialect, DisplaySeparated, JoinConstraint, JoinType, Projection, SetOp};
+    use std::fmt::{self, Display};
+
+    // wraps a `Display` so every line of its rendering (including the
+    // first) is prefixed with one indent unit
+    struct Indented<'a, T>(&'a T, &'a str);
+
+    impl<'a, T: Display> Display for Indented<'a, T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let rendered = self.0.to_string();
+            let mut lines = rendered.split('\n');
+
+            if let Some(first) = lines.next() {
+                write!(f, "{}{}", self.1, first)?;
+            }
+            for line in lines {
+                write!(f, "\n{}{}", self.1, line)?;
             }
 
-            res.push_str(&format!("\n{}USING {}", plus_1_indent, join_col));
-        } else {
-            // second half of join pop
-            let join_r = selects.remove(0);
+            Ok(())
+        }
+    }
 
-            // subqueries
-
-            if selects.len() >= 2 {
-                res.push_str(&format!("{}(\n", plus_1_indent));
-                res.push_str(&Self::sql_subquery(
-                    selects,
-                    joins,
-                    indent,
-                    indent_level + 2,
-                    reverse_nesting,
-                ));
-                res.push_str(&format!("\n{})", plus_1_indent));
-            } else if selects.len() == 1 {
-                let join_l = selects.remove(0);
-                res.push_str(&Self::select_sql(&join_l, indent, indent_level + 1));
+    pub(super) struct Query<'a> {
+        pub with: Vec<NamedCte<'a>>,
+        pub body: SetExpr<'a>,
+        pub order_by: Option<String>,
+        pub limit: Option<u64>,
+        pub offset: Option<u64>,
+        pub create_table: Option<String>,
+        pub dialect: &'a dyn Dialect,
+        pub indent: &'a str,
+    }
+
+    impl<'a> Query<'a> {
+        // wraps a bare `Select` as a minimal query, for embedding as a
+        // named CTE or a derived-table subquery
+        pub(super) fn from_select(select: Select<'a>, dialect: &'a dyn Dialect, indent: &'a str) -> Self {
+            Query {
+                with: vec![],
+                body: SetExpr::Select(Box::new(select)),
+                order_by: None,
+                limit: None,
+                offset: None,
+                create_table: None,
+                dialect,
+                indent,
             }
+        }
+    }
+
+    impl<'a> Display for Query<'a> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let mut body = String::new();
 
-            res.push_str(&format!("\n{}ALL {} JOIN\n", plus_1_indent, join_type));
+            if !self.with.is_empty() {
+                let ctes: Vec<String> = self.with.iter().map(ToString::to_string).collect();
+                body.push_str(&format!("WITH\n{}\n", DisplaySeparated(&ctes, ",\n")));
+            }
 
-            // now write the right side of join
-            res.push_str(&Self::select_sql(&join_r, indent, indent_level + 1));
+            body.push_str(&self.body.to_string());
 
-            res.push_str(&format!("\n{}USING {}", plus_1_indent, join_col));
+            if let Some(ref order_by) = self.order_by {
+                body.push_str(&format!("\nORDER BY {}", order_by));
+            }
+            body.push_str(&self.dialect.limit_offset_sql(self.limit, self.offset));
+
+            if let Some(ref create_table) = self.create_table {
+                write!(f, "CREATE TABLE {} AS\n(\n{}\n)", create_table, Indented(&body, self.indent))
+            } else {
+                write!(f, "{}", body)
+            }
         }
+    }
 
-        res
+    pub(super) struct NamedCte<'a> {
+        pub name: String,
+        pub query: Query<'a>,
     }
 
-    fn select_sql(select: &Select, indent: &str, indent_level: usize) -> String {
-        let base_indent: String = repeat_n(indent, indent_level).collect();
-        let plus_1_indent: String = repeat_n(indent, indent_level + 1).collect();
-        let plus_2_indent: String = repeat_n(indent, indent_level + 2).collect();
+    impl<'a> Display for NamedCte<'a> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{} AS (\n{}\n)", self.name, Indented(&self.query, self.query.indent))
+        }
+    }
 
-        let mut res = String::new();
+    pub(super) enum SetExpr<'a> {
+        Select(Box<Select<'a>>),
+        SetOperation(Box<SetOperation<'a>>),
+    }
 
-        if select.projections.is_empty() {
-            res.push_str(&format!("{}{}\n",
-                plus_1_indent,
-                select.table_name,
-            ));
-        } else {
-            res.push_str(&format!("{}(\n{}SELECT\n{}",
-                base_indent,
-                plus_1_indent,
-                plus_2_indent,
-            ));
-
-            let separator = format!(",\n{}", plus_2_indent);
-            let select_cols = join(select.projections_sql(), &separator);
-            res.push_str(&select_cols);
-            res.push_str(&format!("\n{}FROM {}", plus_1_indent, select.table_name));
-
-            if let Some(ref group_by) = select.group_by {
-                res.push_str(&format!("\n{}GROUP BY {}", plus_1_indent, group_by));
+    impl<'a> Display for SetExpr<'a> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                SetExpr::Select(select) => write!(f, "{}", select),
+                SetExpr::SetOperation(set_op) => write!(f, "{}", set_op),
+            }
+        }
+    }
+
+    pub(super) struct SetOperation<'a> {
+        pub op: SetOp,
+        pub left: SetExpr<'a>,
+        pub right: SetExpr<'a>,
+    }
+
+    impl<'a> Display for SetOperation<'a> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}\n{}\n{}", self.left, self.op, self.right)
+        }
+    }
+
+    // a `SELECT projections FROM from [GROUP BY ...] [WHERE ...] [ORDER BY
+    // ...] [LIMIT/OFFSET ...]` -- used both for a leaf, single-table select
+    // and for the synthetic derived select that heads a multi-way join
+    pub(super) struct Select<'a> {
+        pub projections: Vec<Projection>,
+        pub from: FromItem<'a>,
+        pub group_by: Option<String>,
+        pub where_clause: Option<String>,
+        pub order_by: Option<String>,
+        pub limit: Option<u64>,
+        pub offset: Option<u64>,
+        pub dialect: &'a dyn Dialect,
+        pub indent: &'a str,
+    }
+
+    impl<'a> Select<'a> {
+        pub(super) fn from_domain(select: super::Select, dialect: &'a dyn Dialect, indent: &'a str) -> Self {
+            Select {
+                projections: select.projections,
+                from: FromItem::Table(dialect.quote_ident(&select.table_name)),
+                group_by: select.group_by,
+                where_clause: select.where_clause,
+                order_by: select.order_by,
+                limit: select.limit,
+                offset: select.offset,
+                dialect,
+                indent,
             }
-            if let Some(ref where_clause) = select.where_clause {
-                res.push_str(&format!("\n{}WHERE {}", plus_1_indent, where_clause));
+        }
+    }
+
+    impl<'a> Display for Select<'a> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "SELECT")?;
+
+            let cols = DisplaySeparated(&self.projections, ",\n");
+            write!(f, "\n{}", Indented(&cols, self.indent))?;
+
+            match &self.from {
+                FromItem::Table(name) => write!(f, "\nFROM {}", name)?,
+                from => write!(f, "\nFROM\n{}", Indented(from, self.indent))?,
             }
-            res.push_str(&format!("\n{})", base_indent));
+
+            if let Some(ref group_by) = self.group_by {
+                write!(f, "\nGROUP BY {}", group_by)?;
+            }
+            if let Some(ref where_clause) = self.where_clause {
+                write!(f, "\nWHERE {}", where_clause)?;
+            }
+            if let Some(ref order_by) = self.order_by {
+                write!(f, "\nORDER BY {}", order_by)?;
+            }
+            write!(f, "{}", self.dialect.limit_offset_sql(self.limit, self.offset))?;
+
+            Ok(())
         }
+    }
 
-        res
+    // what follows `FROM` -- a bare table/CTE name, a parenthesized derived
+    // table, or a join of two more `FromItem`s
+    pub(super) enum FromItem<'a> {
+        Table(String),
+        Subquery(Box<Query<'a>>),
+        Join(Box<Join<'a>>),
+    }
+
+    impl<'a> FromItem<'a> {
+        // how a leaf select is referenced as a join operand: a select with
+        // no projections is a passthrough table reference, not wrapped in
+        // its own derived-table subquery
+        pub(super) fn operand_from_domain(select: super::Select, dialect: &'a dyn Dialect, indent: &'a str) -> Self {
+            if select.projections.is_empty() {
+                FromItem::Table(dialect.quote_ident(&select.table_name))
+            } else {
+                let select = Select::from_domain(select, dialect, indent);
+                FromItem::Subquery(Box::new(Query::from_select(select, dialect, indent)))
+            }
+        }
+    }
+
+    impl<'a> Display for FromItem<'a> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                FromItem::Table(name) => write!(f, "{}", name),
+                FromItem::Subquery(query) => write!(f, "(\n{}\n)", Indented(query.as_ref(), query.indent)),
+                FromItem::Join(join) => write!(f, "{}", join),
+            }
+        }
+    }
+
+    pub(super) struct Join<'a> {
+        pub left: FromItem<'a>,
+        pub right: FromItem<'a>,
+        pub operator: JoinOperator<'a>,
+    }
+
+    impl<'a> Display for Join<'a> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}\n{}\n{}\n{}",
+                self.left,
+                self.operator.keyword(),
+                self.right,
+                self.operator.constraint(),
+            )
+        }
+    }
+
+    // a join's dialect-resolved keyword (`ALL LEFT JOIN`, ...) and
+    // constraint (`ON ...`/`USING ...`) -- resolved lazily at format time,
+    // since the same `JoinType`/`JoinConstraint` render differently per dialect
+    pub(super) struct JoinOperator<'a> {
+        pub join_type: JoinType,
+        pub constraint: JoinConstraint,
+        pub dialect: &'a dyn Dialect,
+    }
+
+    impl<'a> JoinOperator<'a> {
+        fn keyword(&self) -> String {
+            self.dialect.join_type_sql(&self.join_type)
+        }
+
+        fn constraint(&self) -> String {
+            self.dialect.constraint_sql(&self.constraint)
+        }
+    }
+
+    impl<'a> Display for JoinOperator<'a> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{} {}", self.keyword(), self.constraint())
+        }
     }
 }
 
@@ -268,22 +752,56 @@ impl std::convert::TryFrom<StatementConfig> for Statement {
         let joins = statement_config.joins.unwrap_or(vec![]).iter()
             .map(|join_config| {
                 let mut join_statement = join_config.split("::::");
-                let join_col = join_statement.next()
-                    .ok_or_else(|| format_err!("No join col"))?
+                let join_expr = join_statement.next()
+                    .ok_or_else(|| format_err!("No join expression"))?
+                    .trim()
                     .to_owned();
                 let local_join_type = match join_statement.next() {
                     Some(s) => s.parse()?,
                     None => global_join_type.clone(), // this is already defaulted if not present, through cli
                 };
 
-                Ok((join_col, local_join_type))
+                // a bare column joins `USING col`; anything containing an
+                // `=` is an arbitrary condition that joins `ON expr`
+                let constraint = if join_expr.contains('=') {
+                    JoinConstraint::On(join_expr)
+                } else {
+                    JoinConstraint::Using(join_expr)
+                };
+
+                Ok(Join { constraint, join_type: local_join_type })
             })
-            .collect::<Result<Vec<(String, JoinType)>, Error>>()?;
+            .collect::<Result<Vec<Join>, Error>>()?;
+
+        let combine = statement_config.combine
+            .map(|combine_config| combine_config.try_into())
+            .transpose()?;
 
         Ok(Self {
             create_table: statement_config.create_table,
             joins,
             selects,
+            order_by: statement_config.order_by,
+            limit: statement_config.limit,
+            offset: statement_config.offset,
+            combine,
+        })
+    }
+}
+
+impl std::convert::TryFrom<CombineConfig> for Combine {
+    type Error = Error;
+
+    fn try_from(combine_config: CombineConfig) -> Result<Self, Self::Error> {
+        let selects: Result<_,_> = combine_config.selects
+            .into_iter()
+            .map(|sc| sc.try_into())
+            .collect();
+        let selects = selects?;
+
+        Ok(Self {
+            op: combine_config.op,
+            selects,
         })
     }
 }
@@ -291,9 +809,12 @@ impl std::convert::TryFrom<StatementConfig> for Statement {
 #[derive(Debug, Clone, Deserialize)]
 struct Select{
     table_name: String,
-    projections: Vec<ProjectionCol>,
+    projections: Vec<Projection>,
     group_by: Option<String>,
     where_clause: Option<String>,
+    order_by: Option<String>,
+    limit: Option<u64>,
+    offset: Option<u64>,
 
 }
 
@@ -303,12 +824,6 @@ impl Select {
             .map(|p| p.aliased())
             .collect()
     }
-
-    fn projections_sql(&self) -> Vec<String> {
-        self.projections.iter()
-            .map(|p| p.sql_string())
-            .collect()
-    }
 }
 
 impl std::convert::TryFrom<SelectConfig> for Select {
@@ -326,30 +841,33 @@ impl std::convert::TryFrom<SelectConfig> for Select {
             projections,
             group_by: select_config.group_by,
             where_clause: select_config.where_clause,
+            order_by: select_config.order_by,
+            limit: select_config.limit,
+            offset: select_config.offset,
 
         })
     }
 }
 
 #[derive(Debug, Clone, Deserialize)]
-struct ProjectionCol {
+struct Projection {
     col: String,
     alias: Option<String>,
 }
 
-impl std::str::FromStr for ProjectionCol {
+impl std::str::FromStr for Projection {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match &s.split(" as ").collect::<Vec<_>>()[..] {
             &[col, alias] => {
-                Ok(ProjectionCol {
+                Ok(Projection {
                     col: col.trim().to_owned(),
                     alias: Some(alias.trim().to_owned()),
                 })
             },
             &[col] => {
-                Ok(ProjectionCol {
+                Ok(Projection {
                     col: col.to_owned(),
                     alias: None,
                 })
@@ -360,15 +878,17 @@ impl std::str::FromStr for ProjectionCol {
 
 }
 
-impl ProjectionCol {
-    fn sql_string(&self) -> String {
+impl Display for Projection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if let Some(ref alias) = self.alias {
-            format!("{} as {}", self.col, alias)
+            write!(f, "{} as {}", self.col, alias)
         } else {
-            format!("{}", self.col)
+            write!(f, "{}", self.col)
         }
     }
+}
 
+impl Projection {
     fn aliased(&self) -> String {
         if let Some(ref alias) = self.alias {
             alias.to_owned()
@@ -428,6 +948,50 @@ impl std::str::FromStr for JoinType {
     }
 }
 
+#[derive(Debug, Clone, Deserialize)]
+enum SetOp {
+    #[serde(alias="union")]
+    #[serde(alias="UNION")]
+    Union,
+
+    #[serde(alias="union_all")]
+    #[serde(alias="UNION_ALL")]
+    UnionAll,
+
+    #[serde(alias="intersect")]
+    #[serde(alias="INTERSECT")]
+    Intersect,
+
+    #[serde(alias="except")]
+    #[serde(alias="EXCEPT")]
+    Except,
+}
+
+impl Display for SetOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SetOp::Union => write!(f, "UNION"),
+            SetOp::UnionAll => write!(f, "UNION ALL"),
+            SetOp::Intersect => write!(f, "INTERSECT"),
+            SetOp::Except => write!(f, "EXCEPT"),
+        }
+    }
+}
+
+impl std::str::FromStr for SetOp {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "union" | "UNION" => Ok(SetOp::Union),
+            "union_all" | "UNION_ALL" => Ok(SetOp::UnionAll),
+            "intersect" | "INTERSECT" => Ok(SetOp::Intersect),
+            "except" | "EXCEPT" => Ok(SetOp::Except),
+            _ => Err(format_err!("Could not parse string to SetOp"))
+        }
+    }
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(name="moarsql")]
 struct CliOpt {
@@ -439,5 +1003,10 @@ struct CliOpt {
 
     #[structopt(long="reverse-nesting")]
     reverse_nesting: bool,
-}
 
+    #[structopt(long="cte")]
+    cte: bool,
+
+    #[structopt(long="dialect")]
+    dialect: Option<String>,
+}